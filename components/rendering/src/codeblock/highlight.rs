@@ -1,10 +1,15 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
 
-use config::highlighting::{SyntaxAndTheme, CLASS_STYLE};
+use config::highlighting::SyntaxAndTheme;
+use errors::{anyhow, Error, Result};
 use syntect::easy::HighlightLines;
 use syntect::highlighting::{Color, Theme};
 use syntect::html::{
-    line_tokens_to_classed_spans, styled_line_to_highlighted_html, ClassStyle, IncludeBackground,
+    css_for_theme_with_class_style, line_tokens_to_classed_spans, styled_line_to_highlighted_html,
+    ClassStyle, IncludeBackground,
 };
 use syntect::parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet};
 use tera::escape_html;
@@ -19,6 +24,9 @@ fn write_css_color(s: &mut String, c: Color) {
 }
 
 pub(crate) struct ClassHighlighter<'config> {
+    lang: String,
+    prefix: String,
+    class_style: ClassStyle,
     syntax_set: &'config SyntaxSet,
     open_spans: isize,
     parse_state: ParseState,
@@ -26,26 +34,36 @@ pub(crate) struct ClassHighlighter<'config> {
 }
 
 impl<'config> ClassHighlighter<'config> {
-    pub fn new(syntax: &SyntaxReference, syntax_set: &'config SyntaxSet) -> Self {
+    pub fn new(syntax: &SyntaxReference, syntax_set: &'config SyntaxSet, prefix: &str) -> Self {
         let parse_state = ParseState::new(syntax);
-        Self { syntax_set, open_spans: 0, parse_state, scope_stack: ScopeStack::new() }
+        Self {
+            lang: syntax.name.clone(),
+            prefix: prefix.to_string(),
+            class_style: spaced_prefixed(prefix),
+            syntax_set,
+            open_spans: 0,
+            parse_state,
+            scope_stack: ScopeStack::new(),
+        }
     }
 
     /// Parse the line of code and update the internal HTML buffer with tagged HTML
     ///
     /// *Note:* This function requires `line` to include a newline at the end and
     /// also use of the `load_defaults_newlines` version of the syntaxes.
-    pub fn highlight_line(&mut self, line: &str) -> String {
+    pub fn highlight_line(&mut self, line: &str) -> Result<String> {
         debug_assert!(line.ends_with('\n'));
-        let parsed_line = self.parse_state.parse_line(line, self.syntax_set);
+        let parsed_line =
+            self.parse_state.parse_line(line, self.syntax_set).map_err(|e| syntax_error(&self.lang, line, e))?;
         let (formatted_line, delta) = line_tokens_to_classed_spans(
             line,
             parsed_line.as_slice(),
-            CLASS_STYLE,
+            self.class_style,
             &mut self.scope_stack,
-        );
+        )
+        .map_err(|e| syntax_error(&self.lang, line, e))?;
         self.open_spans += delta;
-        formatted_line
+        Ok(formatted_line)
     }
 
     /// Close all open `<span>` tags and return the finished HTML string
@@ -59,6 +77,7 @@ impl<'config> ClassHighlighter<'config> {
 }
 
 pub(crate) struct InlineHighlighter<'config> {
+    lang: String,
     theme: &'config Theme,
     fg_color: String,
     bg_color: Color,
@@ -77,18 +96,163 @@ impl<'config> InlineHighlighter<'config> {
         write_css_color(&mut color, theme.settings.foreground.unwrap_or(Color::BLACK));
         let fg_color = format!(r#" style="color:{};""#, color);
         let bg_color = theme.settings.background.unwrap_or(Color::WHITE);
-        Self { theme, fg_color, bg_color, syntax_set, h }
+        Self { lang: syntax.name.clone(), theme, fg_color, bg_color, syntax_set, h }
     }
 
-    pub fn highlight_line(&mut self, line: &str) -> String {
-        let regions = self.h.highlight(line, self.syntax_set);
+    pub fn highlight_line(&mut self, line: &str) -> Result<String> {
+        let regions =
+            self.h.highlight_line(line, self.syntax_set).map_err(|e| syntax_error(&self.lang, line, e))?;
         // TODO: add a param like `IncludeBackground` for `IncludeForeground` in syntect
         let highlighted = styled_line_to_highlighted_html(
             &regions,
             IncludeBackground::IfDifferent(self.bg_color),
-        );
-        highlighted.replace(&self.fg_color, "")
+        )
+        .map_err(|e| syntax_error(&self.lang, line, e))?;
+        Ok(highlighted.replace(&self.fg_color, ""))
+    }
+}
+
+/// Drop ANSI escape sequences and neutralise stray C0 control bytes before a
+/// line reaches the highlighter.
+///
+/// `ESC [ … <final>` sequences (the colour/cursor codes terminals emit) are
+/// removed outright; any remaining control byte is replaced with its printable
+/// Unicode "control picture" (e.g. a lone `\x1b` becomes `␛`) so it can never
+/// end up as an invisible, markup-breaking byte in the output. The newline the
+/// highlighters require and literal tabs are left untouched.
+fn strip_control_chars(line: &str) -> Cow<'_, str> {
+    let needs_scrub = line
+        .bytes()
+        .any(|b| b == 0x1b || b == 0x7f || (b < 0x20 && b != b'\n' && b != b'\t'));
+    if !needs_scrub {
+        return Cow::Borrowed(line);
+    }
+
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' if chars.peek() == Some(&'[') => {
+                // Consume a CSI sequence: `[`, parameter/intermediate bytes
+                // (0x20-0x3f), then a final byte (0x40-0x7e). A copy-pasted
+                // terminal capture can end mid-sequence; if we hit the end of
+                // the line (or any non-CSI byte, e.g. the trailing `\n`) before
+                // a final byte, the sequence is truncated. Rather than swallow
+                // the rest of the line — which would drop real content and strip
+                // the newline the highlighters require — surface it as a
+                // placeholder plus the raw body.
+                chars.next();
+                let mut params = String::new();
+                let mut terminated = false;
+                while let Some(&next) = chars.peek() {
+                    if ('\x20'..='\x3f').contains(&next) {
+                        params.push(next);
+                        chars.next();
+                    } else if ('\x40'..='\x7e').contains(&next) {
+                        chars.next();
+                        terminated = true;
+                        break;
+                    } else {
+                        break;
+                    }
+                }
+                if !terminated {
+                    out.push('\u{241b}');
+                    out.push('[');
+                    out.push_str(&params);
+                }
+            }
+            '\n' | '\t' => out.push(c),
+            c if (c as u32) < 0x20 => {
+                out.push(char::from_u32(0x2400 + c as u32).unwrap_or('\u{fffd}'));
+            }
+            '\x7f' => out.push('\u{2421}'),
+            c => out.push(c),
+        }
     }
+    Cow::Owned(out)
+}
+
+/// Wrap a syntect parse/highlight failure with the fenced block's language and
+/// the offending source line so the user knows which block to fix.
+fn syntax_error(lang: &str, line: &str, source: impl std::fmt::Display) -> Error {
+    anyhow!("Highlighting failed in a `{}` code block on line `{}`: {}", lang, line.trim_end(), source)
+}
+
+/// Build a `ClassStyle` for a runtime (non-`'static`) prefix.
+///
+/// syntect's `ClassStyle::SpacedPrefixed` borrows a `&'static str`, but the
+/// prefix comes from a config `String`. Intern each distinct prefix once so a
+/// repeated config load (e.g. `zola serve`) reuses the same leaked slice rather
+/// than leaking on every call.
+fn spaced_prefixed(prefix: &str) -> ClassStyle {
+    static INTERNED: OnceLock<Mutex<HashMap<String, &'static str>>> = OnceLock::new();
+    let mut interned = INTERNED.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+    let prefix = *interned
+        .entry(prefix.to_string())
+        .or_insert_with(|| Box::leak(prefix.to_string().into_boxed_str()));
+    ClassStyle::SpacedPrefixed { prefix }
+}
+
+/// Build a complete class-based stylesheet for `theme`.
+///
+/// This is the counterpart to the `SyntaxHighlighter::Classed` markup: syntect
+/// only emits the per-scope rules (via `css_for_theme_with_class_style`), so we
+/// prepend the `pre.<prefix>code` background/foreground and the highlighted-line
+/// `pre.<prefix>code mark` colour, reusing the same logic as
+/// `pre_style`/`mark_style`.
+fn theme_css(theme: &Theme, prefix: &str) -> Result<String> {
+    let mut css = String::new();
+
+    css.push_str("pre.");
+    css.push_str(prefix);
+    css.push_str("code {\n background-color:");
+    write_css_color(&mut css, theme.settings.background.unwrap_or(Color::WHITE));
+    css.push_str(";\n color:");
+    write_css_color(&mut css, theme.settings.foreground.unwrap_or(Color::BLACK));
+    css.push_str(";\n}\n");
+
+    // Scope the highlighted-line colour to the code block so it can't clobber
+    // unrelated `<mark>` elements elsewhere on the page.
+    css.push_str("pre.");
+    css.push_str(prefix);
+    css.push_str("code mark {\n background-color:");
+    write_css_color(
+        &mut css,
+        theme.settings.line_highlight.unwrap_or(Color { r: 255, g: 255, b: 0, a: 0 }),
+    );
+    css.push_str(";\n}\n");
+
+    let scopes = css_for_theme_with_class_style(theme, spaced_prefixed(prefix))
+        .map_err(|e| anyhow!("Could not generate the CSS for theme `{}`: {}", theme.name.as_deref().unwrap_or("unknown"), e))?;
+    css.push_str(&scopes);
+
+    Ok(css)
+}
+
+/// Generate the class-based stylesheet for the theme named `theme_name`.
+///
+/// This lets a site that uses class-based highlighting (no inline theme) ship a
+/// stylesheet that actually colours the emitted `<span class="z-...">` markup.
+pub fn class_style_css(theme_name: &str, prefix: &str) -> Result<String> {
+    let theme = config::highlighting::THEME_SET
+        .themes
+        .get(theme_name)
+        .ok_or_else(|| anyhow!("Highlight theme `{}` not found", theme_name))?;
+    theme_css(theme, prefix)
+}
+
+/// Generate one stylesheet per named theme, returned as `(theme name, CSS)`
+/// pairs, so a site can ship e.g. a light and a dark sheet and toggle between
+/// them client-side.
+pub fn class_style_css_for_themes(
+    theme_names: &[String],
+    prefix: &str,
+) -> Result<Vec<(String, String)>> {
+    theme_names
+        .iter()
+        .map(|name| Ok((name.clone(), class_style_css(name, prefix)?)))
+        .collect()
 }
 
 pub(crate) enum SyntaxHighlighter<'config> {
@@ -99,25 +263,31 @@ pub(crate) enum SyntaxHighlighter<'config> {
 }
 
 impl<'config> SyntaxHighlighter<'config> {
-    pub fn new(highlight_code: bool, s: SyntaxAndTheme<'config>) -> Self {
+    pub fn new(highlight_code: bool, s: SyntaxAndTheme<'config>, prefix: &str) -> Self {
         if highlight_code {
             if let Some(theme) = s.theme {
                 SyntaxHighlighter::Inlined(InlineHighlighter::new(s.syntax, s.syntax_set, theme))
             } else {
-                SyntaxHighlighter::Classed(ClassHighlighter::new(s.syntax, s.syntax_set))
+                SyntaxHighlighter::Classed(ClassHighlighter::new(s.syntax, s.syntax_set, prefix))
             }
         } else {
             SyntaxHighlighter::NoHighlight
         }
     }
 
-    pub fn highlight_line(&mut self, line: &str) -> String {
+    pub fn highlight_line(&mut self, line: &str) -> Result<String> {
         use SyntaxHighlighter::*;
 
+        // Code fences pasted from a terminal often carry raw `\x1b[...m` escapes
+        // and other C0 control bytes. Scrub them first so no variant — not even
+        // the `escape_html`-only `NoHighlight` branch — can emit control output.
+        let line = strip_control_chars(line);
+        let line = line.as_ref();
+
         match self {
             Inlined(h) => h.highlight_line(line),
             Classed(h) => h.highlight_line(line),
-            NoHighlight => escape_html(line),
+            NoHighlight => Ok(escape_html(line)),
         }
     }
 
@@ -152,13 +322,7 @@ impl<'config> SyntaxHighlighter<'config> {
         use SyntaxHighlighter::*;
 
         match self {
-            Classed(_) => {
-                if let ClassStyle::SpacedPrefixed { prefix } = CLASS_STYLE {
-                    Some(format!("{}code", prefix))
-                } else {
-                    unreachable!()
-                }
-            }
+            Classed(h) => Some(format!("{}code", h.prefix)),
             Inlined(_) | NoHighlight => None,
         }
     }
@@ -195,11 +359,14 @@ mod tests {
         config.markdown.highlight_code = true;
         let code = "import zen\nz = x + y\nprint('hello')\n";
         let syntax_and_theme = resolve_syntax_and_theme(Some("py"), &config);
-        let mut highlighter =
-            ClassHighlighter::new(syntax_and_theme.syntax, syntax_and_theme.syntax_set);
+        let mut highlighter = ClassHighlighter::new(
+            syntax_and_theme.syntax,
+            syntax_and_theme.syntax_set,
+            "z-",
+        );
         let mut out = String::new();
         for line in LinesWithEndings::from(code) {
-            out.push_str(&highlighter.highlight_line(line));
+            out.push_str(&highlighter.highlight_line(line).unwrap());
         }
         out.push_str(&highlighter.finalize());
 
@@ -208,6 +375,35 @@ mod tests {
         assert!(out.contains("z-"));
     }
 
+    #[test]
+    fn can_highlight_with_custom_prefix() {
+        let mut config = Config::default();
+        config.markdown.highlight_code = true;
+        let code = "import zen\nz = x + y\n";
+        let syntax_and_theme = resolve_syntax_and_theme(Some("py"), &config);
+        let mut highlighter =
+            ClassHighlighter::new(syntax_and_theme.syntax, syntax_and_theme.syntax_set, "foo-");
+        let mut out = String::new();
+        for line in LinesWithEndings::from(code) {
+            out.push_str(&highlighter.highlight_line(line).unwrap());
+        }
+        out.push_str(&highlighter.finalize());
+
+        assert!(out.contains("foo-"));
+        assert!(!out.contains("z-"));
+    }
+
+    #[test]
+    fn pre_class_uses_configured_prefix() {
+        let mut config = Config::default();
+        config.markdown.highlight_code = true;
+        // No inline theme -> class-based highlighting.
+        config.markdown.highlight_theme = "css".to_string();
+        let syntax_and_theme = resolve_syntax_and_theme(Some("py"), &config);
+        let highlighter = SyntaxHighlighter::new(true, syntax_and_theme, "foo-");
+        assert_eq!(highlighter.pre_class().as_deref(), Some("foo-code"));
+    }
+
     #[test]
     fn can_highlight_inline() {
         let mut config = Config::default();
@@ -221,7 +417,7 @@ mod tests {
         );
         let mut out = String::new();
         for line in LinesWithEndings::from(code) {
-            out.push_str(&highlighter.highlight_line(line));
+            out.push_str(&highlighter.highlight_line(line).unwrap());
         }
 
         assert!(out.starts_with(r#"<span style="color"#));
@@ -234,11 +430,89 @@ mod tests {
         config.markdown.highlight_code = false;
         let code = "<script>alert('hello')</script>";
         let syntax_and_theme = resolve_syntax_and_theme(Some("py"), &config);
-        let mut highlighter = SyntaxHighlighter::new(false, syntax_and_theme);
+        let mut highlighter = SyntaxHighlighter::new(false, syntax_and_theme, "z-");
         let mut out = String::new();
         for line in LinesWithEndings::from(code) {
-            out.push_str(&highlighter.highlight_line(line));
+            out.push_str(&highlighter.highlight_line(line).unwrap());
         }
         assert!(!out.contains("<script>"));
     }
+
+    #[test]
+    fn class_style_css_contains_pre_and_mark_rules() {
+        let css = class_style_css("base16-ocean-dark", "z-").unwrap();
+        assert!(css.contains("pre.z-code {"));
+        assert!(css.contains("background-color:"));
+        assert!(css.contains("color:"));
+        assert!(css.contains("pre.z-code mark {"));
+        // The per-scope rules from syntect use the same prefix.
+        assert!(css.contains(".z-comment"));
+    }
+
+    #[test]
+    fn class_style_css_honours_custom_prefix() {
+        let css = class_style_css("base16-ocean-dark", "foo-").unwrap();
+        assert!(css.contains("pre.foo-code {"));
+        assert!(css.contains("pre.foo-code mark {"));
+        assert!(!css.contains("pre.z-code"));
+    }
+
+    #[test]
+    fn class_style_css_errors_on_unknown_theme() {
+        let err = class_style_css("does-not-exist", "z-").unwrap_err();
+        assert!(err.to_string().contains("does-not-exist"));
+    }
+
+    #[test]
+    fn class_style_css_for_themes_emits_one_sheet_per_theme() {
+        let themes = vec!["base16-ocean-dark".to_string(), "base16-ocean-light".to_string()];
+        let sheets = class_style_css_for_themes(&themes, "z-").unwrap();
+        assert_eq!(sheets.len(), 2);
+        assert_eq!(sheets[0].0, "base16-ocean-dark");
+        assert_eq!(sheets[1].0, "base16-ocean-light");
+        assert!(sheets[0].1.contains("pre.z-code {"));
+        assert!(sheets[1].1.contains("pre.z-code {"));
+        // Different themes produce different stylesheets.
+        assert_ne!(sheets[0].1, sheets[1].1);
+    }
+
+    #[test]
+    fn strips_ansi_escape_sequences_before_highlighting() {
+        let mut config = Config::default();
+        config.markdown.highlight_code = false;
+        let code = "\x1b[31mred\x1b[0m\n";
+        let syntax_and_theme = resolve_syntax_and_theme(Some("txt"), &config);
+        let mut highlighter = SyntaxHighlighter::new(false, syntax_and_theme, "z-");
+        let out = highlighter.highlight_line(code).unwrap();
+        assert!(!out.contains('\x1b'));
+        assert!(out.contains("red"));
+    }
+
+    #[test]
+    fn highlight_errors_carry_language_and_line() {
+        // A syntect parse/highlight failure is surfaced through `syntax_error`
+        // rather than panicking; the message names the offending language and
+        // the source line so the user can find the bad fenced block.
+        let err = syntax_error("python", "z = x +\n", "regex backtrack limit exceeded");
+        let msg = err.to_string();
+        assert!(msg.contains("python"));
+        assert!(msg.contains("z = x +"));
+        assert!(msg.contains("regex backtrack limit exceeded"));
+    }
+
+    #[test]
+    fn lone_control_bytes_become_printable_placeholders() {
+        assert_eq!(strip_control_chars("a\x1bb\n"), "a\u{241b}b\n");
+        assert_eq!(strip_control_chars("a\x00b\n"), "a\u{2400}b\n");
+        assert_eq!(strip_control_chars("plain\n"), "plain\n");
+    }
+
+    #[test]
+    fn truncated_escape_keeps_trailing_newline_and_content() {
+        // A partial SGR code, as produced by a copy-pasted terminal capture.
+        let out = strip_control_chars("prefix \x1b[38;5;208\n");
+        assert!(out.ends_with('\n'));
+        assert!(!out.contains('\x1b'));
+        assert_eq!(out, "prefix \u{241b}[38;5;208\n");
+    }
 }